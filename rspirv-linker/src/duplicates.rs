@@ -55,106 +55,295 @@ pub fn remove_duplicate_ext_inst_imports(module: &mut rspirv::dr::Module) {
     }
 }
 
-// TODO: Don't merge zombie types with non-zombie types
-pub fn remove_duplicate_types(module: &mut rspirv::dr::Module) {
-    fn rewrite_inst_with_rules(inst: &mut rspirv::dr::Instruction, rules: &HashMap<u32, u32>) {
-        if let Some(ref mut id) = inst.result_type {
-            // If the rewrite rules contain this ID, replace with the mapped value, otherwise don't touch it.
+fn rewrite_inst_with_rules(inst: &mut rspirv::dr::Instruction, rules: &HashMap<u32, u32>) {
+    if let Some(ref mut id) = inst.result_type {
+        // If the rewrite rules contain this ID, replace with the mapped value, otherwise don't touch it.
+        *id = rules.get(id).copied().unwrap_or(*id);
+    }
+    for op in &mut inst.operands {
+        if let Some(id) = operand_idref_mut(op) {
             *id = rules.get(id).copied().unwrap_or(*id);
         }
-        for op in &mut inst.operands {
-            if let Some(id) = operand_idref_mut(op) {
-                *id = rules.get(id).copied().unwrap_or(*id);
-            }
-        }
     }
+}
 
-    // Keep in mind, this algorithm requires forward type references to not exist - i.e. it's a valid spir-v module.
+// A "local signature" is everything about an instruction except its result id and any id it
+// refers to: opcode and every non-IdRef operand (literals, StorageClass, Dim, ImageFormat, ...).
+// Two instructions can only ever end up in the same partition block if their local signatures are
+// equal. Note that `result_type` is also just an IdRef, and must NOT be baked in here: it's a raw,
+// possibly pre-merge id, so comparing it directly would permanently fragment two nodes that are
+// only congruent because their result types are *themselves* about to merge (e.g. two identical
+// `OpConstant 42` typed by two duplicate `OpTypeInt 32 0`s). Like every other IdRef operand, it
+// only ever participates via the `shape` refinement below, where it's compared by current block
+// instead of by raw id.
+fn local_signature(inst: &rspirv::dr::Instruction) -> Vec<u32> {
     use rspirv::binary::Assemble;
 
-    // When a duplicate type is encountered, then this is a map from the deleted ID, to the new, deduplicated ID.
-    let mut rewrite_rules = HashMap::new();
-    // Instructions are encoded into "keys": their opcode, followed by arguments. Importantly, result_id is left out.
-    // This means that any instruction that declares the same type, but with different result_id, will result in the
-    // same key.
-    let mut key_to_result_id = HashMap::new();
-    // TODO: This is implementing forward pointers incorrectly.
-    let mut unresolved_forward_pointers = HashSet::new();
-
-    for inst in &mut module.types_global_values {
-        if inst.class.opcode == spirv::Op::TypeForwardPointer {
-            if let rspirv::dr::Operand::IdRef(id) = inst.operands[0] {
-                unresolved_forward_pointers.insert(id);
+    let mut data = vec![];
+    data.push(inst.class.opcode as u32);
+    for op in &inst.operands {
+        if !matches!(op, rspirv::dr::Operand::IdRef(_)) {
+            op.assemble_into(&mut data);
+        }
+    }
+    data
+}
+
+// `remove_duplicate_types` used to assemble forward pointers as `IdRef(0)`, which admittedly
+// "implements forward pointers incorrectly - all unresolved forward pointers will compare equal"
+// (so two genuinely distinct mutually-recursive pointer types could be wrongly merged, and the
+// whole algorithm assumed forward references didn't exist in the first place).
+//
+// Instead, this runs a partition-refinement congruence closure (the same fixpoint used for DFA
+// minimization): start with one block per local signature, then repeatedly split any block whose
+// members disagree, for some IdRef operand position, on which block *that* operand currently
+// belongs to. When a full pass makes no more splits, everything remaining in a block is
+// congruent - including ids that only resolve to each other through a cycle of pointers/structs.
+pub fn remove_duplicate_types(module: &mut rspirv::dr::Module) {
+    let len = module.types_global_values.len();
+
+    // `OpTypeForwardPointer` has no result id of its own - it just forward-declares that some
+    // *other*, not-yet-seen id will turn out to be a pointer type. It's never a merge candidate;
+    // it only holds an IdRef to that pointer, which the final rewrite updates like any other.
+    let id_to_pos: HashMap<u32, usize> = module
+        .types_global_values
+        .iter()
+        .enumerate()
+        .filter_map(|(i, inst)| inst.result_id.map(|id| (id, i)))
+        .collect();
+
+    // An IdRef operand that points outside `types_global_values` (e.g. at a function) has no
+    // block of its own; `external_key` gives it a stable key, offset past every real block index
+    // so it can never collide with one, while still comparing equal to itself and distinct from
+    // every other external id (instead of collapsing all of them to one sentinel value).
+    let external_key = |id: u32| len + id as usize;
+
+    // `block_of[i]` is the current block id of node `i`, represented as the index of one member
+    // of the block (always its lowest index); it starts as `i` itself, is first set to the
+    // lowest-indexed node with the same local signature, and after that blocks only ever split.
+    let mut block_of: Vec<usize> = (0..len).collect();
+    {
+        let mut sig_to_block: HashMap<Vec<u32>, usize> = HashMap::new();
+        for (i, inst) in module.types_global_values.iter().enumerate() {
+            if inst.result_id.is_none() {
                 continue;
             }
+            block_of[i] = *sig_to_block.entry(local_signature(inst)).or_insert(i);
         }
-        if inst.class.opcode == spirv::Op::TypePointer
-            && unresolved_forward_pointers.contains(&inst.result_id.unwrap())
-        {
-            unresolved_forward_pointers.remove(&inst.result_id.unwrap());
-        }
-        // This is an important spot: Say that we come upon a duplicated aggregate type (one that references
-        // other types). Its arguments may be duplicated themselves, and so building the key directly will fail
-        // to match up with the first type. However, **because forward references are not allowed**, we're
-        // guaranteed to have already found and deduplicated the argument types! So that means the deduplication
-        // translation is already in rewrite_rules, and we merely need to apply the mapping before generating
-        // the key.
-        // Nit: Overwriting the instruction isn't technically necessary, as it will get handled by the final
-        // all_inst_iter_mut pass below. However, the code is a lil bit cleaner this way I guess.
-        rewrite_inst_with_rules(inst, &rewrite_rules);
+    }
+
+    loop {
+        // For each node, its "shape" given the *current* partition: its own block, followed by
+        // the block of its result_type (if any) and each of its IdRef operands, in order. Two
+        // nodes with the same local signature stay together only if they also agree on this
+        // shape; this is the classic Hopcroft/DFA-minimization refinement fixpoint.
+        let mut shape_to_block: HashMap<Vec<usize>, usize> = HashMap::new();
+        let mut new_block_of = block_of.clone();
+        let mut changed = false;
 
-        let key = {
-            let mut data = vec![];
+        for (i, inst) in module.types_global_values.iter().enumerate() {
+            if inst.result_id.is_none() {
+                continue;
+            }
 
-            data.push(inst.class.opcode as u32);
+            let mut shape = vec![block_of[i]];
             if let Some(id) = inst.result_type {
-                // We're not only deduplicating types here, but constants as well. Those contain result_types, and so we
-                // need to include those here. For example, OpConstant can have the same arg, but different result_type,
-                // and it should not be deduplicated (e.g. the constants 1u8 and 1u16).
-                data.push(id);
+                shape.push(
+                    id_to_pos
+                        .get(&id)
+                        .map_or_else(|| external_key(id), |&j| block_of[j]),
+                );
             }
             for op in &inst.operands {
                 if let rspirv::dr::Operand::IdRef(id) = op {
-                    if unresolved_forward_pointers.contains(id) {
-                        // TODO: This is implementing forward pointers incorrectly. All unresolved forward pointers will
-                        // compare equal.
-                        rspirv::dr::Operand::IdRef(0).assemble_into(&mut data);
-                    } else {
-                        op.assemble_into(&mut data);
-                    }
-                } else {
-                    op.assemble_into(&mut data);
+                    shape.push(
+                        id_to_pos
+                            .get(id)
+                            .map_or_else(|| external_key(*id), |&j| block_of[j]),
+                    );
                 }
             }
 
-            data
-        };
-
-        match key_to_result_id.entry(key) {
-            hash_map::Entry::Vacant(entry) => {
-                // This is the first time we've seen this key. Insert the key into the map, registering this type as
-                // something other types can deduplicate to.
-                entry.insert(inst.result_id.unwrap());
+            let block = *shape_to_block.entry(shape).or_insert(i);
+            if block != block_of[i] {
+                changed = true;
             }
-            hash_map::Entry::Occupied(entry) => {
-                // We've already seen this key. We need to do two things:
-                // 1) Add a rewrite rule from this type to the type that we saw before.
-                let old_value = rewrite_rules.insert(inst.result_id.unwrap(), *entry.get());
-                // 2) Erase this instruction. Because we're iterating over this vec, removing an element is hard, so
-                // clear it with OpNop, and then remove it in the retain() call below.
-                assert!(old_value.is_none());
-                *inst = rspirv::dr::Instruction::new(spirv::Op::Nop, None, None, vec![]);
+            new_block_of[i] = block;
+        }
+
+        block_of = new_block_of;
+        if !changed {
+            break;
+        }
+    }
+
+    // Each surviving block collapses to its representative (the member that ended up as the
+    // block id) - this also soundly merges recursive struct/pointer cycles, since two nodes on a
+    // cycle only land in the same block once every step of the cycle has been shown congruent.
+    let mut rewrite_rules = HashMap::new();
+    for (i, inst) in module.types_global_values.iter().enumerate() {
+        if let Some(id) = inst.result_id {
+            let rep_id = module.types_global_values[block_of[i]].result_id.unwrap();
+            if rep_id != id {
+                rewrite_rules.insert(id, rep_id);
             }
         }
     }
 
-    // We rewrote instructions we wanted to remove with OpNop. Remove them properly.
-    module
-        .types_global_values
-        .retain(|op| op.class.opcode != spirv::Op::Nop);
+    module.types_global_values.retain(|inst| {
+        inst.result_id
+            .map_or(true, |id| !rewrite_rules.contains_key(&id))
+    });
 
-    // Apply the rewrite rules to the whole module
+    // Apply the rewrite rules to the whole module (including the survivors themselves, since a
+    // merged node's operands may still point at other merged-away ids).
     for inst in module.all_inst_iter_mut() {
         rewrite_inst_with_rules(inst, &rewrite_rules);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rspirv::dr::{Instruction, Module, Operand};
+
+    fn int_type(result_id: u32) -> Instruction {
+        Instruction::new(
+            spirv::Op::TypeInt,
+            None,
+            Some(result_id),
+            vec![Operand::LiteralInt32(32), Operand::LiteralInt32(0)],
+        )
+    }
+
+    fn constant_42(result_id: u32, result_type: u32) -> Instruction {
+        Instruction::new(
+            spirv::Op::Constant,
+            Some(result_type),
+            Some(result_id),
+            vec![Operand::LiteralInt32(42)],
+        )
+    }
+
+    // Two duplicate `OpTypeInt 32 0`s, each the result_type of its own duplicate
+    // `OpConstant 42`. Both the types and the constants should merge down to one of each: the
+    // constants are only congruent once their result types are shown congruent, so this only
+    // passes if `result_type` is refined via `shape` instead of baked into the initial partition.
+    #[test]
+    fn merges_constants_whose_result_type_is_also_a_duplicate() {
+        let mut module = Module::default();
+        module.types_global_values = vec![
+            int_type(1),
+            int_type(2),
+            constant_42(3, 1),
+            constant_42(4, 2),
+        ];
+
+        remove_duplicate_types(&mut module);
+
+        assert_eq!(module.types_global_values.len(), 2);
+        let ty_id = module.types_global_values[0].result_id.unwrap();
+        let constant = &module.types_global_values[1];
+        assert_eq!(constant.class.opcode, spirv::Op::Constant);
+        assert_eq!(constant.result_type, Some(ty_id));
+    }
+
+    fn forward_pointer(target: u32) -> Instruction {
+        Instruction::new(
+            spirv::Op::TypeForwardPointer,
+            None,
+            None,
+            vec![
+                Operand::IdRef(target),
+                Operand::StorageClass(spirv::StorageClass::Function),
+            ],
+        )
+    }
+
+    fn pointer_type(result_id: u32, pointee: u32) -> Instruction {
+        Instruction::new(
+            spirv::Op::TypePointer,
+            None,
+            Some(result_id),
+            vec![
+                Operand::StorageClass(spirv::StorageClass::Function),
+                Operand::IdRef(pointee),
+            ],
+        )
+    }
+
+    fn self_referential_struct(result_id: u32, pointer_field: u32) -> Instruction {
+        Instruction::new(
+            spirv::Op::TypeStruct,
+            None,
+            Some(result_id),
+            vec![Operand::IdRef(pointer_field)],
+        )
+    }
+
+    fn forward_pointer_target(inst: &Instruction) -> u32 {
+        match inst.operands[0] {
+            Operand::IdRef(id) => id,
+            _ => panic!("expected IdRef operand"),
+        }
+    }
+
+    // Two copies of a forward-declared, self-referential `struct S { *S }`. The old `IdRef(0)`
+    // hack made any two such structs compare equal whatever their pointers led to; here they
+    // merge only because following the pointer cycle all the way around shows them congruent.
+    // They should collapse to a single struct and a single pointer, with both
+    // `OpTypeForwardPointer`s left in place but rewritten to agree on which id survived -
+    // forward pointers are never themselves merge candidates, only holders of an IdRef.
+    #[test]
+    fn merges_duplicate_self_referential_struct_cycles_through_forward_pointers() {
+        let mut module = Module::default();
+        module.types_global_values = vec![
+            forward_pointer(2),
+            self_referential_struct(3, 2),
+            pointer_type(2, 3),
+            forward_pointer(5),
+            self_referential_struct(6, 5),
+            pointer_type(5, 6),
+        ];
+
+        remove_duplicate_types(&mut module);
+
+        assert_eq!(module.types_global_values.len(), 4);
+
+        let pointers: Vec<&Instruction> = module
+            .types_global_values
+            .iter()
+            .filter(|inst| inst.class.opcode == spirv::Op::TypePointer)
+            .collect();
+        let structs: Vec<&Instruction> = module
+            .types_global_values
+            .iter()
+            .filter(|inst| inst.class.opcode == spirv::Op::TypeStruct)
+            .collect();
+        let forward_pointers: Vec<&Instruction> = module
+            .types_global_values
+            .iter()
+            .filter(|inst| inst.class.opcode == spirv::Op::TypeForwardPointer)
+            .collect();
+
+        assert_eq!(pointers.len(), 1);
+        assert_eq!(structs.len(), 1);
+        assert_eq!(forward_pointers.len(), 2);
+
+        let pointer_id = pointers[0].result_id.unwrap();
+        let struct_id = structs[0].result_id.unwrap();
+
+        // Both forward pointers now agree on the single surviving pointer id, and the cycle
+        // between that pointer and struct is still self-consistent after the rewrite.
+        assert_eq!(forward_pointer_target(forward_pointers[0]), pointer_id);
+        assert_eq!(forward_pointer_target(forward_pointers[1]), pointer_id);
+        assert_eq!(
+            pointers[0].operands[1],
+            rspirv::dr::Operand::IdRef(struct_id)
+        );
+        assert_eq!(
+            structs[0].operands[0],
+            rspirv::dr::Operand::IdRef(pointer_id)
+        );
+    }
+}
@@ -0,0 +1,405 @@
+use rspirv::spirv;
+use std::collections::{HashMap, HashSet};
+
+/// Removes types, constants, capabilities and ext-inst imports that are no longer referenced
+/// by anything in the module. This should run after the `remove_duplicate_*` passes, so that
+/// the ids they merge away (and any operand types that were only used by the duplicates) are
+/// already gone by the time we compute what's still live.
+pub fn remove_unused(module: &mut rspirv::dr::Module) {
+    let live_ids = compute_live_ids(module);
+
+    remove_unused_types_and_constants(module, &live_ids);
+    remove_unused_ext_inst_imports(module);
+    remove_unused_capabilities(module);
+    remove_dead_names_and_decorations(module);
+}
+
+// Computes the set of ids in `types_global_values` (and `ext_inst_imports`) that are reachable
+// from the roots of the module: entry points, their execution modes, anything exported via a
+// Linkage decoration, and the bodies of every function (we don't eliminate dead functions here,
+// so every instruction inside one counts as a use).
+fn compute_live_ids(module: &rspirv::dr::Module) -> HashSet<u32> {
+    // Lookup from result id to the instruction that defines it, so a use can be walked back to
+    // its definition. Only types/constants/ext-inst-imports can be removed by this pass, but it's
+    // simplest to just index everything with a result id.
+    let mut id_to_def = HashMap::new();
+    for inst in module.all_inst_iter() {
+        if let Some(result_id) = inst.result_id {
+            id_to_def.insert(result_id, inst);
+        }
+    }
+
+    let mut live = HashSet::new();
+    let mut worklist = vec![];
+    let mut mark = |id: u32, live: &mut HashSet<u32>, worklist: &mut Vec<u32>| {
+        if live.insert(id) {
+            worklist.push(id);
+        }
+    };
+    let mark_operands =
+        |inst: &rspirv::dr::Instruction, live: &mut HashSet<u32>, worklist: &mut Vec<u32>| {
+            if let Some(result_type) = inst.result_type {
+                if live.insert(result_type) {
+                    worklist.push(result_type);
+                }
+            }
+            for op in &inst.operands {
+                if let rspirv::dr::Operand::IdRef(id)
+                | rspirv::dr::Operand::IdMemorySemantics(id)
+                | rspirv::dr::Operand::IdScope(id) = op
+                {
+                    if live.insert(*id) {
+                        worklist.push(*id);
+                    }
+                }
+            }
+        };
+
+    for inst in &module.entry_points {
+        for op in &inst.operands {
+            if let rspirv::dr::Operand::IdRef(id) = op {
+                mark(*id, &mut live, &mut worklist);
+            }
+        }
+    }
+    for inst in &module.execution_modes {
+        // Not just the entry point: `OpExecutionModeId` also takes ids (e.g. LocalSizeId).
+        mark_operands(inst, &mut live, &mut worklist);
+    }
+    for inst in &module.annotations {
+        if inst.class.opcode == spirv::Op::Decorate
+            && inst.operands.get(1)
+                == Some(&rspirv::dr::Operand::Decoration(
+                    spirv::Decoration::LinkageAttributes,
+                ))
+        {
+            let exported = inst.operands.last().map_or(false, |op| {
+                matches!(
+                    op,
+                    rspirv::dr::Operand::LinkageType(spirv::LinkageType::Export)
+                )
+            });
+            if exported {
+                if let Some(rspirv::dr::Operand::IdRef(id)) = inst.operands.first() {
+                    mark(*id, &mut live, &mut worklist);
+                }
+            }
+        }
+    }
+    // Every instruction belonging to a function body is a use site: this pass only drops dead
+    // types/constants/capabilities/ext-inst-imports, not dead functions.
+    for func in &module.functions {
+        if let Some(def) = &func.def {
+            mark_operands(def, &mut live, &mut worklist);
+        }
+        for param in &func.parameters {
+            mark_operands(param, &mut live, &mut worklist);
+        }
+        for block in &func.blocks {
+            if let Some(label) = &block.label {
+                mark_operands(label, &mut live, &mut worklist);
+            }
+            for inst in &block.instructions {
+                mark_operands(inst, &mut live, &mut worklist);
+            }
+        }
+    }
+
+    // Fixpoint: pull in whatever the definition of each newly-live id references.
+    while let Some(id) = worklist.pop() {
+        if let Some(inst) = id_to_def.get(&id) {
+            mark_operands(inst, &mut live, &mut worklist);
+        }
+    }
+
+    live
+}
+
+fn remove_unused_types_and_constants(module: &mut rspirv::dr::Module, live_ids: &HashSet<u32>) {
+    // `OpTypeForwardPointer` has no result id of its own, so the generic `result_id.map_or(true,
+    // ...)` below would keep it unconditionally - even once the real `OpTypePointer` it forward-
+    // declares has been swept away as dead, leaving an invalid module that declares a pointer type
+    // for an id that no longer exists. Its only id is the IdRef it forward-declares, so that's what
+    // has to agree with the target's own liveness instead.
+    module.types_global_values.retain(|inst| {
+        if inst.class.opcode == spirv::Op::TypeForwardPointer {
+            inst.operands.iter().all(|op| match op {
+                rspirv::dr::Operand::IdRef(id) => live_ids.contains(id),
+                _ => true,
+            })
+        } else {
+            inst.result_id.map_or(true, |id| live_ids.contains(&id))
+        }
+    });
+}
+
+fn remove_unused_ext_inst_imports(module: &mut rspirv::dr::Module) {
+    let mut used_imports = HashSet::new();
+    for inst in module.all_inst_iter() {
+        if inst.class.opcode == spirv::Op::ExtInst {
+            if let Some(rspirv::dr::Operand::IdRef(id)) = inst.operands.first() {
+                used_imports.insert(*id);
+            }
+        }
+    }
+
+    module
+        .ext_inst_imports
+        .retain(|inst| inst.result_id.map_or(true, |id| used_imports.contains(&id)));
+}
+
+// Capabilities have no result id, so liveness has to go the other way: keep a capability only if
+// some instruction still in the module actually requires it. This table only covers the
+// capabilities we know how to prove unnecessary from a handful of structural triggers (integer and
+// float widths, and the Dim/Sampled/Arrayed operands of OpTypeImage); anything not listed here is
+// always kept, since modelling the rest of the SPIR-V capability dependency graph (every
+// instruction and operand combination that implies a capability) is out of scope for this pass.
+fn required_trackable_capabilities(module: &rspirv::dr::Module) -> HashSet<spirv::Capability> {
+    let mut required = HashSet::new();
+    for inst in module.all_inst_iter() {
+        match inst.class.opcode {
+            spirv::Op::TypeInt => {
+                if let Some(rspirv::dr::Operand::LiteralInt32(width)) = inst.operands.first() {
+                    match width {
+                        8 => {
+                            required.insert(spirv::Capability::Int8);
+                        }
+                        16 => {
+                            required.insert(spirv::Capability::Int16);
+                        }
+                        64 => {
+                            required.insert(spirv::Capability::Int64);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            spirv::Op::TypeFloat => {
+                if let Some(rspirv::dr::Operand::LiteralInt32(width)) = inst.operands.first() {
+                    match width {
+                        16 => {
+                            required.insert(spirv::Capability::Float16);
+                        }
+                        64 => {
+                            required.insert(spirv::Capability::Float64);
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            spirv::Op::TypeImage => {
+                required.extend(image_type_capability(inst));
+            }
+            _ => {}
+        }
+    }
+    required
+}
+
+// The Dim/Arrayed/Sampled operands of an OpTypeImage pin down at most one of the trackable
+// capabilities, per the "Dim" table in the SPIR-V spec: whether it's sampled (Sampled == 1) or a
+// storage image (Sampled == 2) picks between a pair of capabilities for that Dim. Any other
+// combination (including every 1D/2D/3D image and non-arrayed cube) returns None.
+fn image_type_capability(inst: &rspirv::dr::Instruction) -> Option<spirv::Capability> {
+    let dim = match inst.operands.get(1) {
+        Some(rspirv::dr::Operand::Dim(d)) => *d,
+        _ => return None,
+    };
+    let arrayed = matches!(
+        inst.operands.get(3),
+        Some(rspirv::dr::Operand::LiteralInt32(1))
+    );
+    let sampled = match inst.operands.get(5) {
+        Some(rspirv::dr::Operand::LiteralInt32(s)) => *s,
+        _ => return None,
+    };
+
+    use spirv::{Capability, Dim};
+    match (dim, arrayed, sampled) {
+        (Dim::DimBuffer, _, 1) => Some(Capability::SampledBuffer),
+        (Dim::DimBuffer, _, 2) => Some(Capability::ImageBuffer),
+        (Dim::DimRect, _, 1) => Some(Capability::SampledRect),
+        (Dim::DimRect, _, 2) => Some(Capability::ImageRect),
+        (Dim::DimSubpassData, ..) => Some(Capability::InputAttachment),
+        (Dim::DimCube, true, 1) => Some(Capability::SampledCubeArray),
+        (Dim::DimCube, true, 2) => Some(Capability::ImageCubeArray),
+        _ => None,
+    }
+}
+
+fn remove_unused_capabilities(module: &mut rspirv::dr::Module) {
+    const TRACKABLE: &[spirv::Capability] = &[
+        spirv::Capability::Int8,
+        spirv::Capability::Int16,
+        spirv::Capability::Int64,
+        spirv::Capability::Float16,
+        spirv::Capability::Float64,
+        spirv::Capability::SampledBuffer,
+        spirv::Capability::ImageBuffer,
+        spirv::Capability::SampledRect,
+        spirv::Capability::ImageRect,
+        spirv::Capability::InputAttachment,
+        spirv::Capability::SampledCubeArray,
+        spirv::Capability::ImageCubeArray,
+    ];
+
+    let required = required_trackable_capabilities(module);
+    module.capabilities.retain(|inst| match inst.operands[0] {
+        rspirv::dr::Operand::Capability(cap) => {
+            !TRACKABLE.contains(&cap) || required.contains(&cap)
+        }
+        _ => true,
+    });
+}
+
+// Now that dead types/constants/capabilities/ext-inst-imports are gone, drop any OpName,
+// OpMemberName or decoration that still points at one of the removed ids, so the module stays
+// valid (decorations and names referencing a nonexistent id fail validation).
+fn remove_dead_names_and_decorations(module: &mut rspirv::dr::Module) {
+    let alive_ids: HashSet<u32> = module
+        .all_inst_iter()
+        .filter_map(|inst| inst.result_id)
+        .collect();
+
+    module
+        .debug_names
+        .retain(|inst| match inst.operands.first() {
+            Some(rspirv::dr::Operand::IdRef(id)) => alive_ids.contains(id),
+            _ => true,
+        });
+    module
+        .annotations
+        .retain(|inst| match inst.operands.first() {
+            Some(rspirv::dr::Operand::IdRef(id)) => alive_ids.contains(id),
+            _ => true,
+        });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rspirv::dr::{Instruction, Module, Operand};
+
+    fn capability(cap: spirv::Capability) -> Instruction {
+        Instruction::new(
+            spirv::Op::Capability,
+            None,
+            None,
+            vec![Operand::Capability(cap)],
+        )
+    }
+
+    #[test]
+    fn int8_capability_is_dropped_when_no_8_bit_int_type_is_present() {
+        let mut module = Module::default();
+        module.capabilities = vec![
+            capability(spirv::Capability::Shader),
+            capability(spirv::Capability::Int8),
+        ];
+
+        remove_unused_capabilities(&mut module);
+
+        assert_eq!(
+            module.capabilities,
+            vec![capability(spirv::Capability::Shader)]
+        );
+    }
+
+    #[test]
+    fn int8_capability_is_kept_while_an_8_bit_int_type_is_present() {
+        let mut module = Module::default();
+        module.capabilities = vec![capability(spirv::Capability::Int8)];
+        module.types_global_values = vec![Instruction::new(
+            spirv::Op::TypeInt,
+            None,
+            Some(1),
+            vec![Operand::LiteralInt32(8), Operand::LiteralInt32(0)],
+        )];
+
+        remove_unused_capabilities(&mut module);
+
+        assert_eq!(
+            module.capabilities,
+            vec![capability(spirv::Capability::Int8)]
+        );
+    }
+
+    #[test]
+    fn sampled_buffer_image_requires_sampled_buffer_capability() {
+        let image = Instruction::new(
+            spirv::Op::TypeImage,
+            None,
+            Some(1),
+            vec![
+                Operand::IdRef(2),
+                Operand::Dim(spirv::Dim::DimBuffer),
+                Operand::LiteralInt32(0),
+                Operand::LiteralInt32(0),
+                Operand::LiteralInt32(0),
+                Operand::LiteralInt32(1),
+                Operand::ImageFormat(spirv::ImageFormat::Unknown),
+            ],
+        );
+        assert_eq!(
+            image_type_capability(&image),
+            Some(spirv::Capability::SampledBuffer)
+        );
+    }
+
+    #[test]
+    fn unused_type_is_removed_and_its_name_goes_with_it() {
+        let mut module = Module::default();
+        module.types_global_values =
+            vec![Instruction::new(spirv::Op::TypeVoid, None, Some(1), vec![])];
+        module.debug_names = vec![Instruction::new(
+            spirv::Op::Name,
+            None,
+            None,
+            vec![Operand::IdRef(1), Operand::LiteralString("unused".into())],
+        )];
+
+        remove_unused(&mut module);
+
+        assert!(module.types_global_values.is_empty());
+        assert!(module.debug_names.is_empty());
+    }
+
+    #[test]
+    fn unused_forward_declared_self_referential_struct_is_removed_as_a_whole() {
+        // %2 = a Function-storage pointer to %3, forward-declared since %3's body needs to
+        // refer to %2 before %2 itself is defined; %3 = struct { %2 }. Nothing outside this
+        // cycle references either id, so all three instructions should go together - leaving
+        // the forward pointer behind (declaring a now-nonexistent %2) would be an invalid module.
+        let mut module = Module::default();
+        module.types_global_values = vec![
+            Instruction::new(
+                spirv::Op::TypeForwardPointer,
+                None,
+                None,
+                vec![
+                    Operand::IdRef(2),
+                    Operand::StorageClass(spirv::StorageClass::Function),
+                ],
+            ),
+            Instruction::new(
+                spirv::Op::TypeStruct,
+                None,
+                Some(3),
+                vec![Operand::IdRef(2)],
+            ),
+            Instruction::new(
+                spirv::Op::TypePointer,
+                None,
+                Some(2),
+                vec![
+                    Operand::StorageClass(spirv::StorageClass::Function),
+                    Operand::IdRef(3),
+                ],
+            ),
+        ];
+
+        remove_unused(&mut module);
+
+        assert!(module.types_global_values.is_empty());
+    }
+}